@@ -0,0 +1,581 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use serialport::DataBits;
+use serialport::Parity;
+use serialport::SerialPort;
+use serialport::SerialPortType;
+use serialport::StopBits;
+
+use thiserror::Error;
+
+// How many times a single block is retransmitted before giving up.
+const MAX_BLOCK_RETRIES: u32 = 3;
+
+// Wall-clock deadline for the radio to enter bootloader mode and announce
+// itself with 0xa5.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 30;
+
+// Read-back opcode: request the 32-byte block stored at the given index so it
+// can be compared against what we wrote.
+const READ_BLOCK_CMD: u8 = 0xa3;
+
+// Checksum-echo opcode: ask the bootloader to return the accumulated checksum
+// of the block at the given index. Used as a fallback when the bootloader has
+// no block read opcode.
+const CHECKSUM_ECHO_CMD: u8 = 0xa4;
+
+// Magic bytes expected at the start of a TDH3 firmware image.
+const TDH3_MAGIC: &[u8] = b"TDH3";
+
+/// Known radio / programming-cable USB `(VID, PID)` pairs used to auto-detect
+/// the serial port. Add new cables here.
+pub const KNOWN_USB_IDS: &[(u16, u16)] = &[
+	(0x1a86, 0x7523), // QinHeng CH340 serial bridge (common TDH3 cable)
+	(0x10c4, 0xea60), // Silicon Labs CP210x
+];
+
+/// Errors surfaced by the flasher. [`FlashError::exit_code`] maps each variant
+/// to a process status; failures the CLI already reported keep their old codes.
+#[derive(Error, Debug)]
+pub enum FlashError {
+	#[error("Error reading file '{path}': {source}")]
+	File { path: String, source: io::Error },
+	#[error("'{path}' is not the correct size to be a valid firmware file")]
+	Filesize { path: String },
+	#[error("firmware header does not match the expected TDH3 layout")]
+	BadHeader,
+	#[error("firmware CRC mismatch: trailer 0x{expected:08x}, computed 0x{actual:08x}")]
+	Crc { expected: u32, actual: u32 },
+	#[error("Error opening device '{device}': {source}")]
+	Device { device: String, source: serialport::Error },
+	#[error("No radio programming cable found (looked for known USB VID:PIDs)")]
+	NoDevice,
+	#[error("Multiple candidate serial ports found: {0} — specify one explicitly")]
+	AmbiguousDevice(String),
+	#[error("Error writing init data: {0}")]
+	InitWrite(io::Error),
+	#[error("Serial read unexpected data during handshake")]
+	Handshake,
+	#[error("radio not detected — did you hold PTT while powering on?")]
+	NotDetected,
+	#[error("Write error at {offset}b: {source}")]
+	Write { offset: i32, source: io::Error },
+	#[error("Ack read error at {offset}b")]
+	Ack { offset: i32 },
+	#[error("Block at {offset}b not acknowledged after {retries} retries")]
+	MaxRetries { offset: i32, retries: u32 },
+	#[error("Verify mismatch at block {block} (offset {offset}b)")]
+	Verify { block: i32, offset: i32 },
+	#[error("No read-back response at block {block} (offset {offset}b) — bootloader may not support verification")]
+	VerifyRead { block: i32, offset: i32 },
+}
+
+impl FlashError {
+	/// Exit code the CLI reports for this failure.
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			FlashError::File { .. } => 1,
+			FlashError::Filesize { .. } => 2,
+			FlashError::BadHeader => 2,
+			FlashError::Crc { .. } => 2,
+			FlashError::Device { .. } => 3,
+			FlashError::NoDevice => 3,
+			FlashError::AmbiguousDevice(_) => 3,
+			FlashError::InitWrite(_) => 4,
+			FlashError::Handshake => 4,
+			FlashError::NotDetected => 3,
+			FlashError::Write { .. } => 5,
+			FlashError::Ack { .. } => 6,
+			FlashError::MaxRetries { .. } => 7,
+			FlashError::Verify { .. } => 8,
+			FlashError::VerifyRead { .. } => 8,
+		}
+	}
+}
+
+/// A validated firmware image, ready to be flashed.
+pub struct Firmware {
+	content: Vec<u8>,
+}
+
+impl Firmware {
+	/// Read and size-check a firmware file.
+	pub fn load(path: &str) -> Result<Firmware, FlashError> {
+		let content = fs::read(Path::new(path)).map_err(|source| FlashError::File {
+			path: path.to_owned(),
+			source,
+		})?;
+		let fw = Firmware { content };
+		fw.check(path)?;
+		Ok(fw)
+	}
+
+	/// Raw (unpadded) length of the image.
+	pub fn len(&self) -> usize {
+		self.content.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.content.is_empty()
+	}
+
+	/// Length rounded up to the 32-byte block granularity the bootloader uses.
+	pub fn padded_length(&self) -> i32 {
+		get_padded_length(&self.content)
+	}
+
+	fn check(&self, path: &str) -> Result<(), FlashError> {
+		let len = self.padded_length();
+		if !(40000..=65536).contains(&len) {
+			return Err(FlashError::Filesize { path: path.to_owned() });
+		}
+		Ok(())
+	}
+
+	/// Validate the image's structure before any bytes are sent: a leading TDH3
+	/// magic header and a trailing CRC-32 over the payload. This layout is not
+	/// part of the stock TDH3 image format (a raw binary with neither), so it is
+	/// opt-in behind the CLI's `--verify-crc` flag for toolchains that wrap their
+	/// builds this way, and guards against flashing a wrong file that merely
+	/// happens to fall in the size window.
+	pub fn check_integrity(&self) -> Result<(), FlashError> {
+		if self.content.len() < TDH3_MAGIC.len() + 4
+			|| &self.content[..TDH3_MAGIC.len()] != TDH3_MAGIC
+		{
+			return Err(FlashError::BadHeader);
+		}
+
+		let split = self.content.len() - 4;
+		let expected = u32::from_le_bytes([
+			self.content[split],
+			self.content[split + 1],
+			self.content[split + 2],
+			self.content[split + 3],
+		]);
+		let actual = crc32(&self.content[..split]);
+		if actual != expected {
+			return Err(FlashError::Crc { expected, actual });
+		}
+		Ok(())
+	}
+}
+
+/// Transfer progress events emitted by [`Flasher::flash`] so a frontend — the
+/// default CLI sink, a TUI bar, or a GUI window — can render them.
+pub enum Progress {
+	/// Still waiting for the radio to enter bootloader mode.
+	HandshakeWaiting,
+	/// A block was written and acknowledged.
+	BlockWritten { index: i32, total: i32, bytes: i32 },
+	/// The whole image has been flashed.
+	Done,
+}
+
+/// Sink the flasher pushes [`Progress`] events to.
+pub type ProgressCb<'a> = &'a mut dyn FnMut(Progress);
+
+/// Summary returned from a successful flash.
+pub struct FlashReport {
+	pub retransmissions: u32,
+}
+
+/// An open connection to a radio in bootloader mode.
+pub struct Flasher {
+	port: Box<dyn SerialPort>,
+	/// Byte the bootloader is expected to return after each block. The TDH3
+	/// bootloader's ack value is undocumented, so the default (`None`) keeps the
+	/// baseline behaviour — any byte read back counts as an ack, only a read
+	/// timeout triggers a retransmit. Set a concrete value with
+	/// [`Flasher::set_expected_ack`] once it is known.
+	expected_ack: Option<u8>,
+}
+
+impl Flasher {
+	/// Enumerate the serial ports and return the single one whose USB
+	/// VID:PID matches a [`KNOWN_USB_IDS`] entry, mirroring dmrconfig's
+	/// `find_path(vid, pid)` lookup. Errors if zero or more than one candidate
+	/// is present.
+	pub fn detect_device() -> Result<String, FlashError> {
+		let ports = serialport::available_ports().map_err(|source| FlashError::Device {
+			device: "auto".to_owned(),
+			source,
+		})?;
+
+		let mut matches: Vec<String> = Vec::new();
+		for port in ports {
+			if let SerialPortType::UsbPort(info) = &port.port_type {
+				if KNOWN_USB_IDS.iter().any(|(vid, pid)| *vid == info.vid && *pid == info.pid) {
+					matches.push(port.port_name.clone());
+				}
+			}
+		}
+
+		match matches.len() {
+			0 => Err(FlashError::NoDevice),
+			1 => Ok(matches.remove(0)),
+			_ => Err(FlashError::AmbiguousDevice(matches.join(", "))),
+		}
+	}
+
+	/// Open the serial device the radio's programming cable presents.
+	pub fn open(device: &str) -> Result<Flasher, FlashError> {
+		let baud_rate: u32 = 115200;
+		let port = serialport::new(device, baud_rate)
+			.stop_bits(StopBits::One)
+			.data_bits(DataBits::Eight)
+			.parity(Parity::None)
+			.timeout(Duration::from_millis(500))
+			.flow_control(serialport::FlowControl::None)
+			.open()
+			.map_err(|source| FlashError::Device {
+				device: device.to_owned(),
+				source,
+			})?;
+		Ok(Flasher { port, expected_ack: None })
+	}
+
+	/// Set the byte the bootloader returns to positively acknowledge a block.
+	/// When unset, any byte read back is accepted (baseline behaviour).
+	pub fn set_expected_ack(&mut self, ack: Option<u8>) {
+		self.expected_ack = ack;
+	}
+
+	/// Wait for the radio handshake, then stream the image block-by-block,
+	/// validating each block's ack and retransmitting on mismatch.
+	pub fn flash(&mut self, fw: &Firmware, progress: ProgressCb) -> Result<FlashReport, FlashError> {
+		self.handshake(progress)?;
+
+		let len = fw.padded_length();
+		let total_blocks = len / 32;
+		let mut padded_data: Vec<u8> = fw.content.clone();
+		if padded_data.len() != len as usize {
+			padded_data.resize((len + 32) as usize, 0);
+		}
+
+		let mut retransmissions: u32 = 0;
+		for blk in 0..total_blocks {
+			let chunk = &padded_data[(blk * 32) as usize..((blk * 32) + 32) as usize];
+
+			let mut packet: Vec<u8> = vec![0; 4];
+			packet[0] = 0xa1;
+			if (blk * 32) + 32 >= len {
+				packet[0] += 1;
+			}
+			packet[1] = ((blk >> 8) & 0xff) as u8;
+			packet[2] = (blk & 0xff) as u8;
+			for b in chunk {
+				packet[3] = packet[3].wrapping_add(*b);
+			}
+			packet.extend(chunk);
+
+			// Write the block and wait for a positive ack, retransmitting the
+			// very same packet on a NAK or read timeout before giving up.
+			let mut attempt: u32 = 0;
+			loop {
+				self.port.write_all(packet.as_ref()).map_err(|source| FlashError::Write {
+					offset: blk * 32,
+					source,
+				})?;
+				self.port.flush().map_err(|source| FlashError::Write {
+					offset: blk * 32,
+					source,
+				})?;
+
+				// A hard read error (as opposed to a timeout) is reported as
+				// Ack; an exhausted retry budget is MaxRetries below.
+				let ack = read_ack(self.port.as_mut())
+					.map_err(|_source| FlashError::Ack { offset: blk * 32 })?;
+				let acked = match self.expected_ack {
+					// A specific ack byte is required.
+					Some(expected) => ack == expected as i16,
+					// Baseline: any byte is an ack, -1 means timeout/error.
+					None => ack != -1,
+				};
+				if acked {
+					break;
+				}
+
+				if attempt >= MAX_BLOCK_RETRIES {
+					return Err(FlashError::MaxRetries {
+						offset: blk * 32,
+						retries: MAX_BLOCK_RETRIES,
+					});
+				}
+				attempt += 1;
+				retransmissions += 1;
+			}
+
+			progress(Progress::BlockWritten {
+				index: blk,
+				total: total_blocks,
+				bytes: (blk + 1) * 32,
+			});
+		}
+
+		progress(Progress::Done);
+		Ok(FlashReport { retransmissions })
+	}
+
+	/// Read the programmed image back block-by-block and compare it against the
+	/// padded source, mirroring dmrconfig's serial_read_region pattern: one read
+	/// command per region, a fixed-size reply, reassembled at the write
+	/// granularity.
+	///
+	/// Caveat: the TDH3 bootloader's read-back and checksum-echo opcodes
+	/// ([`READ_BLOCK_CMD`]/[`CHECKSUM_ECHO_CMD`]) are not documented. A bootloader
+	/// that does not implement them will not respond and this returns
+	/// [`FlashError::VerifyRead`] even though the flash itself succeeded, so a
+	/// `VerifyRead` result cannot distinguish "corrupt flash" from "verification
+	/// unsupported".
+	pub fn verify(&mut self, fw: &Firmware) -> Result<(), FlashError> {
+		let len = fw.padded_length();
+		let mut padded_data: Vec<u8> = fw.content.clone();
+		if padded_data.len() != len as usize {
+			padded_data.resize(len as usize, 0);
+		}
+
+		for blk in 0..(len / 32) {
+			let expected = &padded_data[(blk * 32) as usize..((blk * 32) + 32) as usize];
+
+			let cmd: Vec<u8> = vec![
+				READ_BLOCK_CMD,
+				((blk >> 8) & 0xff) as u8,
+				(blk & 0xff) as u8,
+			];
+			self.port.write_all(cmd.as_ref()).map_err(|source| FlashError::Write {
+				offset: blk * 32,
+				source,
+			})?;
+			self.port.flush().map_err(|source| FlashError::Write {
+				offset: blk * 32,
+				source,
+			})?;
+
+			let mut got: Vec<u8> = vec![0; 32];
+			match self.port.read_exact(got.as_mut_slice()) {
+				Ok(()) => {}
+				Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+					// No reply at all. On the very first block assume the
+					// bootloader has no read opcode and fall back to
+					// checksum-echo verification; later on it is a genuine
+					// stall, which is not a byte mismatch.
+					if blk == 0 {
+						return self.verify_checksum(&padded_data, len);
+					}
+					return Err(FlashError::VerifyRead { block: blk, offset: blk * 32 });
+				}
+				Err(_source) => {
+					return Err(FlashError::VerifyRead { block: blk, offset: blk * 32 });
+				}
+			}
+
+			if got.as_slice() != expected {
+				return Err(FlashError::Verify {
+					block: blk,
+					offset: blk * 32,
+				});
+			}
+		}
+		Ok(())
+	}
+
+	/// Checksum-echo fallback for bootloaders without a block read opcode: ask
+	/// for each block's accumulated checksum (the same byte sent in `packet[3]`
+	/// during the write) and compare it against the locally computed value.
+	fn verify_checksum(&mut self, padded_data: &[u8], len: i32) -> Result<(), FlashError> {
+		for blk in 0..(len / 32) {
+			let chunk = &padded_data[(blk * 32) as usize..((blk * 32) + 32) as usize];
+			let mut expected: u8 = 0;
+			for b in chunk {
+				expected = expected.wrapping_add(*b);
+			}
+
+			let cmd: Vec<u8> = vec![
+				CHECKSUM_ECHO_CMD,
+				((blk >> 8) & 0xff) as u8,
+				(blk & 0xff) as u8,
+			];
+			self.port.write_all(cmd.as_ref()).map_err(|source| FlashError::Write {
+				offset: blk * 32,
+				source,
+			})?;
+			self.port.flush().map_err(|source| FlashError::Write {
+				offset: blk * 32,
+				source,
+			})?;
+
+			let got = read_ack(self.port.as_mut())
+				.map_err(|_source| FlashError::VerifyRead { block: blk, offset: blk * 32 })?;
+			if got == -1 {
+				return Err(FlashError::VerifyRead { block: blk, offset: blk * 32 });
+			}
+			if got != expected as i16 {
+				return Err(FlashError::Verify {
+					block: blk,
+					offset: blk * 32,
+				});
+			}
+		}
+		Ok(())
+	}
+
+	/// Wait for the radio to announce itself with 0xa5 and send the init packet
+	/// that puts it into block-receive mode. Following crosvm's dedicated serial
+	/// reader thread, the blocking read runs on a background thread feeding a
+	/// channel while the main loop enforces an overall wall-clock deadline — so
+	/// a radio that is never put into bootloader mode fails cleanly instead of
+	/// spinning on timeouts.
+	fn handshake(&mut self, progress: ProgressCb) -> Result<(), FlashError> {
+		let mut reader = self.port.try_clone().map_err(|source| FlashError::Device {
+			device: "handshake".to_owned(),
+			source,
+		})?;
+
+		let stop = Arc::new(AtomicBool::new(false));
+		let (tx, rx) = mpsc::channel::<u8>();
+		let reader_stop = Arc::clone(&stop);
+		let handle = thread::spawn(move || {
+			let mut buf = [0u8; 1];
+			while !reader_stop.load(Ordering::Relaxed) {
+				match reader.read(&mut buf) {
+					Ok(n) if n > 0 => {
+						if tx.send(buf[0]).is_err() {
+							break;
+						}
+					}
+					Ok(_) => {}
+					Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+					Err(_) => break,
+				}
+			}
+		});
+
+		let deadline = Instant::now() + Duration::from_secs(HANDSHAKE_TIMEOUT_SECS);
+		let result = self.handshake_loop(&rx, deadline, progress);
+
+		// Tear the reader thread down so it stops consuming serial bytes before
+		// the block transfer begins.
+		stop.store(true, Ordering::Relaxed);
+		drop(rx);
+		let _ = handle.join();
+		result
+	}
+
+	fn handshake_loop(
+		&mut self,
+		rx: &mpsc::Receiver<u8>,
+		deadline: Instant,
+		progress: ProgressCb,
+	) -> Result<(), FlashError> {
+		let mut found = false;
+		loop {
+			if !found && Instant::now() >= deadline {
+				return Err(FlashError::NotDetected);
+			}
+			match rx.recv_timeout(Duration::from_millis(200)) {
+				Ok(0xa5) => {
+					if !found {
+						found = true;
+						let init: Vec<u8> = vec![
+							0xA0, 0xEE, 0x74, 0x71, 0x07, 0x74, 0x55, 0x55,
+							0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+							0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+							0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+							0x55, 0x55, 0x55, 0x55];
+						self.port.write_all(init.as_ref()).map_err(FlashError::InitWrite)?;
+					}
+				}
+				Ok(_) => {
+					return Err(FlashError::Handshake);
+				}
+				Err(mpsc::RecvTimeoutError::Timeout) => {
+					if found {
+						// Radio has gone quiet after acking init; proceed.
+						break;
+					}
+					progress(Progress::HandshakeWaiting);
+				}
+				Err(mpsc::RecvTimeoutError::Disconnected) => {
+					break;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+fn get_padded_length(file_content: &[u8]) -> i32 {
+	let content_length = file_content.len();
+	let len: i32 = ((content_length as f64 / 32.0).ceil() as i32) * 32;
+	len
+}
+
+// CRC-32 (IEEE 802.3) over the firmware payload, computed bit-by-bit to avoid
+// pulling in a dependency for the single call site.
+fn crc32(data: &[u8]) -> u32 {
+	let mut crc: u32 = 0xffff_ffff;
+	for &byte in data {
+		crc ^= byte as u32;
+		for _ in 0..8 {
+			if crc & 1 != 0 {
+				crc = (crc >> 1) ^ 0xedb8_8320;
+			} else {
+				crc >>= 1;
+			}
+		}
+	}
+	!crc
+}
+
+// Read a single ack byte, distinguishing a benign timeout (-1) from a hard
+// I/O error (propagated to the caller).
+fn read_ack(port: &mut dyn SerialPort) -> io::Result<i16> {
+	let mut buf = [0u8; 1];
+	match port.read(&mut buf) {
+		Ok(n) if n > 0 => Ok(buf[0] as i16),
+		Ok(_) => Ok(-1),
+		Err(ref e) if e.kind() == io::ErrorKind::TimedOut => Ok(-1),
+		Err(e) => Err(e),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn padded_length_rounds_up_to_32() {
+		assert_eq!(get_padded_length(&[0u8; 0]), 0);
+		assert_eq!(get_padded_length(&[0u8; 1]), 32);
+		assert_eq!(get_padded_length(&[0u8; 32]), 32);
+		assert_eq!(get_padded_length(&[0u8; 33]), 64);
+	}
+
+	#[test]
+	fn size_check_accepts_in_window_and_rejects_outside() {
+		let ok = Firmware { content: vec![0u8; 40000] };
+		assert!(ok.check("fw.bin").is_ok());
+
+		let too_small = Firmware { content: vec![0u8; 100] };
+		assert!(matches!(too_small.check("fw.bin"), Err(FlashError::Filesize { .. })));
+
+		let too_big = Firmware { content: vec![0u8; 70000] };
+		assert!(matches!(too_big.check("fw.bin"), Err(FlashError::Filesize { .. })));
+	}
+
+	#[test]
+	fn crc32_matches_known_vector() {
+		// CRC-32/ISO-HDLC check value for the ASCII string "123456789".
+		assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+		assert_eq!(crc32(&[]), 0);
+	}
+}